@@ -1,19 +1,28 @@
 extern crate docopt;
 extern crate postgres;
+extern crate r2d2;
+extern crate r2d2_postgres;
+extern crate reqwest;
+extern crate serde_json;
 #[macro_use]
 extern crate serde_derive;
 
 use docopt::Docopt;
 use postgres::{Connection, TlsMode};
+use r2d2_postgres::PostgresConnectionManager;
+use std::collections::{HashMap, HashSet};
 use std::{thread, time};
 
+type Pool = r2d2::Pool<PostgresConnectionManager>;
+
 const USAGE: &'static str = "
 birdwatcher.
 
 Usage:
-  birdwatcher install [-c <connection>] [--tls]
-  birdwatcher scan [-i <interval>] [--reset] [-c <connection>] [--tls]
-  birdwatcher report [-c <connection>] [--tls]
+  birdwatcher install [-c <connection>]... [--tls]
+  birdwatcher scan [-i <interval>] [--reset] [-c <connection>]... [--tls] [--mode <mode>] [--pool-size <n>] [--alert-after <seconds>] [--webhook <url>] [--telegram <token_chat_id>]
+  birdwatcher report [-c <connection>]... [--tls] [--since <timestamp>] [--format <format>]
+  birdwatcher blocking [-c <connection>]... [--tls]
   birdwatcher (-h | --help)
   birdwatcher --version
 
@@ -21,83 +30,407 @@ Options:
   -h --help                                     Show this screen.
   --version                                     Show version.
   -i <interval>, --interval <interval>          Scan interval in ms [default: 100].
-  -c <connection>, --connection <connection>    The connection string [default: postgres://postgres@localhost:5432].
+  -c <connection>, --connection <connection>    The connection string, may be repeated to watch several databases.
   --tls                                         Enable TLS for database connection.
   --reset                                       Reset the report table.
+  --mode <mode>                                 Only track locks whose mode matches exactly (e.g. AccessExclusiveLock).
+  --pool-size <n>                               Max pooled connections per database [default: 5].
+  --alert-after <seconds>                       Notify when a tracked lock's age crosses this threshold, in seconds.
+  --webhook <url>                               POST a JSON alert to this URL when the threshold is crossed.
+  --telegram <token_chat_id>                    Notify a Telegram chat, given as <bot-token>:<chat-id>.
+  --since <timestamp>                           Review historical contention starting at this timestamp, including released locks.
+  --format <format>                              Report output format: text, json or csv [default: text].
 ";
 
+const DEFAULT_CONNECTION: &'static str = "postgres://postgres@localhost:5432";
+
 #[derive(Debug, Deserialize)]
 struct Args {
     flag_interval: u64,
     flag_reset: bool,
     flag_tls: bool,
-    flag_connection: Option<String>,
+    flag_connection: Vec<String>,
+    flag_mode: Option<String>,
+    flag_pool_size: u32,
+    flag_alert_after: Option<i64>,
+    flag_webhook: Option<String>,
+    flag_telegram: Option<String>,
+    flag_since: Option<String>,
+    flag_format: String,
     cmd_install: bool,
     cmd_scan: bool,
     cmd_report: bool,
+    cmd_blocking: bool,
+}
+
+#[derive(Debug, Clone)]
+enum Notifier {
+    Webhook(String),
+    Telegram { token: String, chat_id: String },
+}
+
+fn build_notifier(args: &Args) -> Option<Notifier> {
+    if let Some(url) = &args.flag_webhook {
+        return Some(Notifier::Webhook(url.clone()));
+    }
+    if let Some(spec) = &args.flag_telegram {
+        // bot tokens themselves contain a colon (<bot_id>:<auth>), so split off
+        // the chat-id from the right instead of the token from the left.
+        return match spec.rsplitn(2, ':').collect::<Vec<_>>()[..] {
+            [chat_id, token] => Some(Notifier::Telegram {
+                token: token.to_string(),
+                chat_id: chat_id.to_string(),
+            }),
+            _ => {
+                eprintln!("--telegram expects <bot-token>:<chat-id>, got {:?}", spec);
+                ::std::process::exit(1);
+            }
+        };
+    }
+    None
+}
+
+#[derive(Debug, Clone)]
+struct AlertConfig {
+    after_seconds: i64,
+    notifier: Option<Notifier>,
 }
 
-fn connect(url: String, tls: TlsMode) -> Connection {
+#[derive(Debug, Serialize)]
+struct AlertPayload {
+    pid: i32,
+    db: String,
+    relation: String,
+    mode: String,
+    query: String,
+    age_seconds: i64,
+    resolved: bool,
+}
+
+fn send_webhook(client: &reqwest::Client, url: &str, payload: &AlertPayload) {
+    if let Err(error) = client.post(url).json(payload).send() {
+        eprintln!("couldn't deliver webhook alert: {:?}", error);
+    }
+}
+
+fn send_telegram(client: &reqwest::Client, token: &str, chat_id: &str, payload: &AlertPayload) {
+    let text = if payload.resolved {
+        format!(
+            "✅ resolved: pid {} on {}.{} (held at least {}s)",
+            payload.pid, payload.db, payload.relation, payload.age_seconds
+        )
+    } else {
+        format!(
+            "🚨 {} held on {}.{} by pid {} for {}s\n{}",
+            payload.mode, payload.db, payload.relation, payload.pid, payload.age_seconds, payload.query
+        )
+    };
+    let url = format!("https://api.telegram.org/bot{}/sendMessage", token);
+    let body = serde_json::json!({ "chat_id": chat_id, "text": text });
+    if let Err(error) = client.post(&url).json(&body).send() {
+        eprintln!("couldn't deliver telegram alert: {:?}", error);
+    }
+}
+
+fn notify(client: &reqwest::Client, notifier: &Notifier, payload: &AlertPayload) {
+    match notifier {
+        Notifier::Webhook(url) => send_webhook(client, url, payload),
+        Notifier::Telegram { token, chat_id } => send_telegram(client, token, chat_id, payload),
+    }
+}
+
+fn target_urls(flag_connection: Vec<String>) -> Vec<String> {
+    if flag_connection.is_empty() {
+        vec![DEFAULT_CONNECTION.to_string()]
+    } else {
+        flag_connection
+    }
+}
+
+fn build_pool(url: String, tls: TlsMode, pool_size: u32) -> Pool {
     println!("connecting to database {:?}", url);
-    return match Connection::connect(url, tls) {
-        Ok(connection) => connection,
+    let manager = match PostgresConnectionManager::new(url.as_str(), tls) {
+        Ok(manager) => manager,
         Err(error) => {
-            eprintln!("oops, there was a problem connecting to the base: {?}", error);
+            eprintln!("oops, there was a problem connecting to the base: {:?}", error);
             ::std::process::exit(1);
         }
     };
+    match r2d2::Pool::builder().max_size(pool_size).build(manager) {
+        Ok(pool) => pool,
+        Err(error) => {
+            eprintln!("oops, couldn't build connection pool: {:?}", error);
+            ::std::process::exit(1);
+        }
+    }
 }
 
-const DROP_TABLE: &'static str = "DROP TABLE IF EXISTS lockTracking";
-const CREATE_TABLE: &'static str = "CREATE TABLE IF NOT EXISTS lockTracking (
-	mode TEXT,
-	pid INTEGER, 
-	db TEXT,
-	relation TEXT, 
+const DROP_TABLE: &'static str = "
+DROP VIEW IF EXISTS lock_state;
+DROP TRIGGER IF EXISTS lock_events_set_age ON lock_events;
+DROP FUNCTION IF EXISTS set_lock_age();
+DROP TABLE IF EXISTS lock_events;
+DROP TABLE IF EXISTS sessions;
+DROP TABLE IF EXISTS relations;
+DROP TABLE IF EXISTS lockTracking";
+
+const CREATE_TABLE: &'static str = "
+CREATE TABLE IF NOT EXISTS relations (
+	id SERIAL PRIMARY KEY,
+	oid OID NOT NULL UNIQUE,
+	relname TEXT NOT NULL,
+	datname TEXT NOT NULL
+);
+
+CREATE TABLE IF NOT EXISTS sessions (
+	id SERIAL PRIMARY KEY,
+	pid INTEGER NOT NULL,
+	backend_start TIMESTAMP WITH TIME ZONE NOT NULL,
 	username TEXT,
-	application TEXT, 
-	startedAt TIMESTAMP WITH TIME ZONE,
-	age INTERVAL, 
-	query TEXT
-)";
+	application TEXT,
+	UNIQUE (pid, backend_start)
+);
+
+CREATE TABLE IF NOT EXISTS lock_events (
+	id SERIAL PRIMARY KEY,
+	relation_id INTEGER NOT NULL REFERENCES relations (id),
+	session_id INTEGER NOT NULL REFERENCES sessions (id),
+	mode TEXT NOT NULL,
+	granted BOOLEAN NOT NULL,
+	query TEXT,
+	started_at TIMESTAMP WITH TIME ZONE NOT NULL,
+	released_at TIMESTAMP WITH TIME ZONE,
+	age INTERVAL,
+	UNIQUE (relation_id, session_id, mode, started_at)
+);
+
+CREATE INDEX IF NOT EXISTS lock_events_relation_started_idx ON lock_events (relation_id, started_at);
+CREATE INDEX IF NOT EXISTS lock_events_mode_idx ON lock_events (mode);
+
+CREATE OR REPLACE FUNCTION set_lock_age() RETURNS TRIGGER AS $$
+BEGIN
+	NEW.age := age(COALESCE(NEW.released_at, clock_timestamp()), NEW.started_at);
+	RETURN NEW;
+END;
+$$ LANGUAGE plpgsql;
+
+DROP TRIGGER IF EXISTS lock_events_set_age ON lock_events;
+CREATE TRIGGER lock_events_set_age
+BEFORE INSERT OR UPDATE ON lock_events
+FOR EACH ROW EXECUTE PROCEDURE set_lock_age();
 
-fn install(connection: Connection) {
-    println!("installing table lockTracking");
-    match connection.execute(DROP_TABLE, &[]) {
+CREATE OR REPLACE VIEW lock_state AS
+SELECT DISTINCT ON (s.pid, le.relation_id)
+	s.pid,
+	r.datname AS db,
+	r.relname AS relation,
+	le.mode,
+	le.granted,
+	s.username,
+	s.application,
+	le.started_at,
+	le.released_at,
+	CASE WHEN le.released_at IS NULL THEN age(clock_timestamp(), le.started_at) ELSE le.age END AS age,
+	le.query
+FROM lock_events le
+JOIN sessions s ON s.id = le.session_id
+JOIN relations r ON r.id = le.relation_id
+ORDER BY s.pid, le.relation_id, le.started_at DESC";
+
+fn install(connection: &Connection) {
+    println!("installing relations/sessions/lock_events tables");
+    match connection.batch_execute(DROP_TABLE) {
         Ok(_) => (),
-        Err(error) => eprintln!("couldn't remove table: {:?}", error.to_string()),
+        Err(error) => eprintln!("couldn't remove existing tables: {:?}", error.to_string()),
     }
 
-    match connection.execute(CREATE_TABLE, &[]) {
+    match connection.batch_execute(CREATE_TABLE) {
         Ok(_) => println!("ready to scan!"),
         Err(error) => {
-            eprintln!("oops, couldn't install table: {:?}", error.to_string());
+            eprintln!("oops, couldn't install schema: {:?}", error.to_string());
             std::process::exit(1);
         }
     }
 }
 
-const INSERT_INTO: &'static str = "INSERT INTO locktracking(mode, pid, db, relation, username, application, startedAt, age, query) 
-			SELECT l.mode, l.pid, a.datname, c.relname, a.usename, a.application_name,  a.query_start, age(clock_timestamp(), a.query_start), query
-			FROM pg_catalog.pg_locks l JOIN pg_class c ON l.relation = c.oid
-			JOIN pg_catalog.pg_stat_activity a ON l.pid = a.pid
-			WHERE granted = true AND mode = 'AccessExclusiveLock'";
+const UPSERT_RELATIONS: &'static str = "
+INSERT INTO relations (oid, relname, datname)
+SELECT DISTINCT c.oid, c.relname, a.datname
+FROM pg_catalog.pg_locks l
+JOIN pg_catalog.pg_class c ON l.relation = c.oid
+JOIN pg_catalog.pg_stat_activity a ON l.pid = a.pid
+ON CONFLICT (oid) DO NOTHING";
+
+const UPSERT_SESSIONS: &'static str = "
+INSERT INTO sessions (pid, backend_start, username, application)
+SELECT DISTINCT a.pid, a.backend_start, a.usename, a.application_name
+FROM pg_catalog.pg_stat_activity a
+JOIN pg_catalog.pg_locks l ON l.pid = a.pid
+ON CONFLICT (pid, backend_start) DO NOTHING";
+
+const INSERT_INTO: &'static str = "
+INSERT INTO lock_events (relation_id, session_id, mode, granted, query, started_at)
+SELECT r.id, s.id, l.mode, l.granted, a.query, a.query_start
+FROM pg_catalog.pg_locks l
+JOIN pg_catalog.pg_class c ON l.relation = c.oid
+JOIN pg_catalog.pg_stat_activity a ON l.pid = a.pid
+JOIN relations r ON r.oid = c.oid
+JOIN sessions s ON s.pid = a.pid AND s.backend_start = a.backend_start";
+
+const INSERT_ON_CONFLICT: &'static str = "
+ON CONFLICT (relation_id, session_id, mode, started_at)
+DO UPDATE SET granted = EXCLUDED.granted
+WHERE lock_events.granted != EXCLUDED.granted";
+
+const COUNT_HELD_LOCKS: &'static str = "
+SELECT count(*)
+FROM pg_catalog.pg_locks l
+JOIN pg_catalog.pg_stat_activity a ON l.pid = a.pid
+WHERE l.granted";
+
+const COUNT_HELD_LOCKS_MODE_FILTER: &'static str = " AND l.mode = $1";
+
+const MARK_RELEASED: &'static str = "
+UPDATE lock_events le
+SET released_at = clock_timestamp()
+WHERE le.released_at IS NULL
+AND NOT EXISTS (
+	SELECT 1
+	FROM pg_catalog.pg_locks l
+	JOIN pg_catalog.pg_class c ON l.relation = c.oid
+	JOIN pg_catalog.pg_stat_activity a ON l.pid = a.pid
+	JOIN sessions s ON s.pid = a.pid AND s.backend_start = a.backend_start
+	JOIN relations r ON r.oid = c.oid
+	WHERE s.id = le.session_id AND r.id = le.relation_id AND l.mode = le.mode AND a.query_start = le.started_at
+)";
+
+const ALERT_CANDIDATES: &'static str = "
+SELECT a.pid, a.datname, c.relname, l.mode, a.query, EXTRACT(EPOCH FROM age(clock_timestamp(), a.query_start))::BIGINT
+FROM pg_catalog.pg_locks l
+JOIN pg_catalog.pg_class c ON l.relation = c.oid
+JOIN pg_catalog.pg_stat_activity a ON l.pid = a.pid
+WHERE l.granted";
+
+const ALERT_CANDIDATES_MODE_FILTER: &'static str = " AND l.mode = $1";
+
+fn check_alerts(
+    label: &str,
+    connection: &Connection,
+    config: &AlertConfig,
+    mode: &Option<String>,
+    alerted: &mut HashMap<(i32, String), AlertPayload>,
+    http_client: &reqwest::Client,
+) {
+    let rows = match mode {
+        Some(m) => {
+            let statement = format!("{}{}", ALERT_CANDIDATES, ALERT_CANDIDATES_MODE_FILTER);
+            connection.query(&statement, &[m])
+        }
+        None => connection.query(ALERT_CANDIDATES, &[]),
+    };
+    let rows = match rows {
+        Ok(rows) => rows,
+        Err(error) => {
+            eprintln!("[{}] couldn't check alert thresholds: {:?}", label, error.to_string());
+            return;
+        }
+    };
 
-fn scan(connection: Connection, interval: u64) {
-    println!("scanning for locks...");
+    let mut still_offending: HashSet<(i32, String)> = HashSet::new();
+    for row in &rows {
+        let age_seconds: i64 = row.get(5);
+        if age_seconds < config.after_seconds {
+            continue;
+        }
+        let payload = AlertPayload {
+            pid: row.get(0),
+            db: row.get(1),
+            relation: row.get(2),
+            mode: row.get(3),
+            query: row.get(4),
+            age_seconds,
+            resolved: false,
+        };
+        let key = (payload.pid, payload.relation.clone());
+        still_offending.insert(key.clone());
+        if !alerted.contains_key(&key) {
+            println!(
+                "[{}] 🚨 {} held on {}.{} by pid {} for {}s",
+                label, payload.mode, payload.db, payload.relation, payload.pid, payload.age_seconds
+            );
+            if let Some(notifier) = &config.notifier {
+                notify(http_client, notifier, &payload);
+            }
+            alerted.insert(key, payload);
+        }
+    }
+
+    let resolved_keys: Vec<(i32, String)> = alerted
+        .keys()
+        .filter(|key| !still_offending.contains(*key))
+        .cloned()
+        .collect();
+    for key in resolved_keys {
+        let mut payload = alerted.remove(&key).unwrap();
+        payload.resolved = true;
+        println!("[{}] ✅ resolved: pid {} on {}.{}", label, payload.pid, payload.db, payload.relation);
+        if let Some(notifier) = &config.notifier {
+            notify(http_client, notifier, &payload);
+        }
+    }
+}
+
+fn scan(label: &str, connection: &Connection, interval: u64, mode: &Option<String>, alert: &Option<AlertConfig>) {
+    println!("[{}] scanning for locks...", label);
+    if let Some(m) = mode {
+        println!("[{}] filtering on mode = {:?}", label, m);
+    }
+    let insert_statement = match mode {
+        Some(_) => format!("{} WHERE l.mode = $1{}", INSERT_INTO, INSERT_ON_CONFLICT),
+        None => format!("{}{}", INSERT_INTO, INSERT_ON_CONFLICT),
+    };
+    let count_statement = match mode {
+        Some(_) => format!("{}{}", COUNT_HELD_LOCKS, COUNT_HELD_LOCKS_MODE_FILTER),
+        None => COUNT_HELD_LOCKS.to_string(),
+    };
     let mut i = 0;
-    let mut previously_found = 0;
+    let mut previously_held = -1;
+    let mut alerted: HashMap<(i32, String), AlertPayload> = HashMap::new();
+    let http_client = reqwest::Client::new();
     loop {
-        let found = match connection.execute(INSERT_INTO, &[]) {
-            Ok(found) => found,
+        if let Err(error) = connection.execute(UPSERT_RELATIONS, &[]) {
+            eprintln!("[{}] couldn't upsert relations: {:?}", label, error.to_string());
+        }
+        if let Err(error) = connection.execute(UPSERT_SESSIONS, &[]) {
+            eprintln!("[{}] couldn't upsert sessions: {:?}", label, error.to_string());
+        }
+        let insert_result = match mode {
+            Some(m) => connection.execute(&insert_statement, &[m]),
+            None => connection.execute(&insert_statement, &[]),
+        };
+        if let Err(error) = insert_result {
+            eprintln!("[{}] couldn't scan locks: {:?}", label, error.to_string());
+        }
+        let count_result = match mode {
+            Some(m) => connection.query(&count_statement, &[m]),
+            None => connection.query(&count_statement, &[]),
+        };
+        let held: i64 = match count_result {
+            Ok(rows) => rows.get(0).get(0),
             Err(error) => {
-                eprintln!("couldn't scan locks: {:?}", error.to_string());
-                0;
-            },
+                eprintln!("[{}] couldn't count held locks: {:?}", label, error.to_string());
+                previously_held
+            }
         };
-        if i == 0 || found != previously_found {
-            println!("{} lock(s) found", found);
-            previously_found = found;
+        if i == 0 || held != previously_held {
+            println!("[{}] {} lock(s) currently held", label, held);
+            previously_held = held;
+        }
+        if let Err(error) = connection.execute(MARK_RELEASED, &[]) {
+            eprintln!("[{}] couldn't mark released locks: {:?}", label, error.to_string());
+        }
+        if let Some(config) = alert {
+            check_alerts(label, connection, config, mode, &mut alerted, &http_client);
         }
         i = (i + 1) % 50;
         thread::sleep(time::Duration::from_millis(interval));
@@ -105,10 +438,19 @@ fn scan(connection: Connection, interval: u64) {
 }
 
 const REPORT: &'static str =
-    "SELECT pid, db, relation, startedAt, query, MAX(age) as duration FROM locktracking
-GROUP by pid, db, relation, startedAt, query
-ORDER BY startedAt";
+    "SELECT pid, db, relation, started_at::text, query, age::text, released_at::text FROM lock_state ORDER BY started_at";
+
+const REPORT_SINCE: &'static str = "
+SELECT s.pid, r.datname AS db, r.relname AS relation, le.started_at::text, le.query,
+	(CASE WHEN le.released_at IS NULL THEN age(clock_timestamp(), le.started_at) ELSE le.age END)::text AS age,
+	le.released_at::text
+FROM lock_events le
+JOIN sessions s ON s.id = le.session_id
+JOIN relations r ON r.id = le.relation_id
+WHERE le.started_at >= $1::text::timestamptz
+ORDER BY le.started_at";
 
+#[derive(Serialize)]
 struct DetectedLock {
     pid: i32,
     db: String,
@@ -116,26 +458,225 @@ struct DetectedLock {
     started_at: String,
     query: String,
     age: String,
+    released_at: Option<String>,
 }
 
-fn report(connection: Connection) {
-    let mut i = 0;
-    let rows = connection.query(REPORT, &[]).unwrap();
+fn csv_field(value: &str) -> String {
+    if value.contains(',') || value.contains('"') || value.contains('\n') {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
+
+fn print_text(locks: &[DetectedLock]) {
+    for (i, lock) in locks.iter().enumerate() {
+        let status = match &lock.released_at {
+            Some(released) => format!("released at {}", released),
+            None => "active".to_string(),
+        };
+        println!("🔒{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}", i, lock.pid, lock.db, lock.relation, lock.started_at, lock.query, lock.age, status);
+    }
+}
+
+fn print_json(locks: &[DetectedLock]) {
+    match serde_json::to_string_pretty(locks) {
+        Ok(json) => println!("{}", json),
+        Err(error) => eprintln!("couldn't serialize report as json: {:?}", error),
+    }
+}
+
+fn print_csv(locks: &[DetectedLock]) {
+    println!("pid,db,relation,started_at,query,age,released_at");
+    for lock in locks {
+        println!(
+            "{},{},{},{},{},{},{}",
+            lock.pid,
+            csv_field(&lock.db),
+            csv_field(&lock.relation),
+            csv_field(&lock.started_at),
+            csv_field(&lock.query),
+            csv_field(&lock.age),
+            csv_field(lock.released_at.as_ref().map(String::as_str).unwrap_or(""))
+        );
+    }
+}
+
+fn report(connection: &Connection, since: &Option<String>, format: &str) {
+    let rows = match since {
+        Some(ts) => connection.query(REPORT_SINCE, &[ts]),
+        None => connection.query(REPORT, &[]),
+    };
+    let rows = rows.unwrap();
     if rows.len() == 0 {
         println!("no lock have been detected 🎉");
         return;
     }
-    for row in &rows {
-        let lock = DetectedLock {
+    let locks: Vec<DetectedLock> = rows
+        .iter()
+        .map(|row| DetectedLock {
             pid: row.get(0),
             db: row.get(1),
             relation: row.get(2),
             started_at: row.get(3),
             query: row.get(4),
             age: row.get(5),
+            released_at: row.get(6),
+        })
+        .collect();
+
+    match format {
+        "json" => print_json(&locks),
+        "csv" => print_csv(&locks),
+        _ => print_text(&locks),
+    }
+}
+
+const ACTIVE_PIDS: &'static str = "
+SELECT pid, query, age(clock_timestamp(), query_start)::text
+FROM pg_catalog.pg_stat_activity
+WHERE pid != pg_backend_pid() AND query_start IS NOT NULL";
+
+const BLOCKING_PIDS: &'static str = "SELECT unnest(pg_blocking_pids($1))";
+
+struct Activity {
+    query: String,
+    age: String,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Color {
+    White,
+    Gray,
+    Black,
+}
+
+// Iterative DFS with three-color marking: a Gray node reached again on the
+// current stack closes a deadlock-prone cycle, which we report as the slice
+// of the stack between the two visits.
+fn detect_cycles(edges: &HashMap<i32, Vec<i32>>) -> Vec<Vec<i32>> {
+    let mut colors: HashMap<i32, Color> = HashMap::new();
+    let mut cycles: Vec<Vec<i32>> = Vec::new();
+    let empty: Vec<i32> = Vec::new();
+
+    let mut starts: Vec<i32> = edges.keys().cloned().collect();
+    starts.sort();
+    for start in starts {
+        if *colors.get(&start).unwrap_or(&Color::White) != Color::White {
+            continue;
+        }
+        let mut stack: Vec<(i32, usize)> = vec![(start, 0)];
+        colors.insert(start, Color::Gray);
+        while let Some(&(node, idx)) = stack.last() {
+            let children = edges.get(&node).unwrap_or(&empty);
+            if idx < children.len() {
+                let next = children[idx];
+                stack.last_mut().unwrap().1 += 1;
+                match *colors.get(&next).unwrap_or(&Color::White) {
+                    Color::White => {
+                        colors.insert(next, Color::Gray);
+                        stack.push((next, 0));
+                    }
+                    Color::Gray => {
+                        let pos = stack.iter().position(|&(p, _)| p == next).unwrap();
+                        cycles.push(stack[pos..].iter().map(|&(p, _)| p).collect());
+                    }
+                    Color::Black => (),
+                }
+            } else {
+                colors.insert(node, Color::Black);
+                stack.pop();
+            }
+        }
+    }
+    cycles
+}
+
+fn print_chain(
+    pid: i32,
+    blocks: &HashMap<i32, Vec<i32>>,
+    info: &HashMap<i32, Activity>,
+    depth: usize,
+    visited: &mut HashSet<i32>,
+) {
+    let indent = "  ".repeat(depth);
+    if !visited.insert(pid) {
+        println!("{}pid {} (already shown above)", indent, pid);
+        return;
+    }
+    match info.get(&pid) {
+        Some(activity) => println!("{}pid {} waiting {} — {}", indent, pid, activity.age, activity.query),
+        None => println!("{}pid {}", indent, pid),
+    }
+    if let Some(children) = blocks.get(&pid) {
+        for &child in children {
+            print_chain(child, blocks, info, depth + 1, visited);
+        }
+    }
+}
+
+fn blocking(connection: &Connection) {
+    println!("building wait-for graph...");
+    let rows = connection.query(ACTIVE_PIDS, &[]).unwrap();
+
+    let mut info: HashMap<i32, Activity> = HashMap::new();
+    for row in &rows {
+        let pid: i32 = row.get(0);
+        info.insert(pid, Activity { query: row.get(1), age: row.get(2) });
+    }
+
+    // directed edges blocked -> blocker, built from pg_blocking_pids(pid)
+    let mut blocked_by: HashMap<i32, Vec<i32>> = HashMap::new();
+    for row in &rows {
+        let pid: i32 = row.get(0);
+        let blockers = match connection.query(BLOCKING_PIDS, &[&pid]) {
+            Ok(blockers) => blockers,
+            Err(error) => {
+                eprintln!("couldn't fetch blockers for pid {}: {:?}", pid, error.to_string());
+                continue;
+            }
         };
-        println!("🔒{}\t{}\t{}\t{}\t{}\t{}\t{}", i, lock.pid, lock.db, lock.relation, lock.started_at, lock.query, lock.age);
-        i = i + 1;
+        let blocker_pids: Vec<i32> = blockers.iter().map(|r| r.get(0)).collect();
+        if !blocker_pids.is_empty() {
+            blocked_by.insert(pid, blocker_pids);
+        }
+    }
+
+    if blocked_by.is_empty() {
+        println!("no blocking detected 🎉");
+        return;
+    }
+
+    let mut blocks: HashMap<i32, Vec<i32>> = HashMap::new();
+    for (&blocked, blockers) in &blocked_by {
+        for &blocker in blockers {
+            blocks.entry(blocker).or_insert_with(Vec::new).push(blocked);
+        }
+    }
+
+    let roots: Vec<i32> = blocks
+        .keys()
+        .cloned()
+        .filter(|pid| !blocked_by.contains_key(pid))
+        .collect();
+    for root in roots {
+        println!("⛔ pid {} is blocking:", root);
+        let mut visited: HashSet<i32> = HashSet::new();
+        visited.insert(root);
+        if let Some(children) = blocks.get(&root) {
+            for &child in children {
+                print_chain(child, &blocks, &info, 1, &mut visited);
+            }
+        }
+    }
+
+    let cycles = detect_cycles(&blocked_by);
+    if !cycles.is_empty() {
+        println!("🔁 deadlock-prone cycle(s) detected:");
+        for cycle in &cycles {
+            let chain: Vec<String> = cycle.iter().map(|pid| pid.to_string()).collect();
+            println!("  {}", chain.join(" -> "));
+        }
     }
 }
 
@@ -144,14 +685,134 @@ fn main() {
         .and_then(|d| d.deserialize())
         .unwrap_or_else(|e| e.exit());
     println!("{:?}", args);
-    let connection = connect(args.flag_connection.unwrap(), TlsMode::None);
+
+    let urls = target_urls(args.flag_connection);
+    let pools: Vec<(String, Pool)> = urls
+        .into_iter()
+        .map(|url| {
+            let pool = build_pool(url.clone(), TlsMode::None, args.flag_pool_size);
+            (url, pool)
+        })
+        .collect();
+
     if args.cmd_install {
-        install(connection);
+        for (url, pool) in &pools {
+            match pool.get() {
+                Ok(connection) => install(&connection),
+                Err(error) => eprintln!("[{}] couldn't check out a connection: {:?}", url, error),
+            }
+        }
     } else if args.cmd_scan {
-        scan(connection, args.flag_interval);
+        let notifier = build_notifier(&args);
+        let alert = args.flag_alert_after.map(|after_seconds| AlertConfig {
+            after_seconds,
+            notifier,
+        });
+        let handles: Vec<_> = pools
+            .into_iter()
+            .map(|(url, pool)| {
+                let interval = args.flag_interval;
+                let mode = args.flag_mode.clone();
+                let alert = alert.clone();
+                thread::spawn(move || match pool.get() {
+                    Ok(connection) => scan(&url, &connection, interval, &mode, &alert),
+                    Err(error) => eprintln!("[{}] couldn't check out a connection: {:?}", url, error),
+                })
+            })
+            .collect();
+        for handle in handles {
+            handle.join().unwrap();
+        }
     } else if args.cmd_report {
-        report(connection);
+        for (url, pool) in &pools {
+            println!("-- {} --", url);
+            match pool.get() {
+                Ok(connection) => report(&connection, &args.flag_since, &args.flag_format),
+                Err(error) => eprintln!("[{}] couldn't check out a connection: {:?}", url, error),
+            }
+        }
+    } else if args.cmd_blocking {
+        for (url, pool) in &pools {
+            println!("-- {} --", url);
+            match pool.get() {
+                Ok(connection) => blocking(&connection),
+                Err(error) => eprintln!("[{}] couldn't check out a connection: {:?}", url, error),
+            }
+        }
     } else {
         panic!("No command specified");
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn detect_cycles_finds_a_simple_cycle() {
+        let mut edges: HashMap<i32, Vec<i32>> = HashMap::new();
+        edges.insert(1, vec![2]);
+        edges.insert(2, vec![3]);
+        edges.insert(3, vec![2]);
+
+        let cycles = detect_cycles(&edges);
+
+        assert_eq!(cycles.len(), 1);
+        assert_eq!(cycles[0], vec![2, 3]);
+    }
+
+    #[test]
+    fn detect_cycles_reports_nothing_for_a_dag() {
+        let mut edges: HashMap<i32, Vec<i32>> = HashMap::new();
+        edges.insert(1, vec![2]);
+        edges.insert(2, vec![3]);
+
+        assert!(detect_cycles(&edges).is_empty());
+    }
+
+    #[test]
+    fn csv_field_passes_through_plain_values() {
+        assert_eq!(csv_field("AccessExclusiveLock"), "AccessExclusiveLock");
+    }
+
+    #[test]
+    fn csv_field_quotes_commas_quotes_and_newlines() {
+        assert_eq!(
+            csv_field("select 1, \"x\"\nselect 2"),
+            "\"select 1, \"\"x\"\"\nselect 2\""
+        );
+    }
+
+    fn test_args(telegram: Option<String>) -> Args {
+        Args {
+            flag_interval: 100,
+            flag_reset: false,
+            flag_tls: false,
+            flag_connection: vec![],
+            flag_mode: None,
+            flag_pool_size: 5,
+            flag_alert_after: None,
+            flag_webhook: None,
+            flag_telegram: telegram,
+            flag_since: None,
+            flag_format: "text".to_string(),
+            cmd_install: false,
+            cmd_scan: false,
+            cmd_report: false,
+            cmd_blocking: false,
+        }
+    }
+
+    #[test]
+    fn build_notifier_splits_telegram_token_on_last_colon() {
+        let args = test_args(Some("123:ABC:chat".to_string()));
+
+        match build_notifier(&args) {
+            Some(Notifier::Telegram { token, chat_id }) => {
+                assert_eq!(token, "123:ABC");
+                assert_eq!(chat_id, "chat");
+            }
+            other => panic!("expected a Telegram notifier, got {:?}", other),
+        }
+    }
+}